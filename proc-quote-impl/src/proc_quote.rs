@@ -30,13 +30,133 @@ impl Error {
     }
 }
 
-type Result<T> = std::result::Result<T, Error>; 
+type Result<T> = std::result::Result<T, Error>;
+
+/// A single buffered token, with groups holding their pre-built inner buffer so
+/// descending into them never re-iterates the input.
+enum Entry {
+    Group(Group, TokenBuffer),
+    Ident(Ident),
+    Punct(Punct),
+    Literal(Literal),
+}
+
+impl Entry {
+    /// The original token this entry was built from.
+    fn to_token_tree(&self) -> TokenTree {
+        match self {
+            Entry::Group(group, _) => TokenTree::Group(group.clone()),
+            Entry::Ident(ident) => TokenTree::Ident(ident.clone()),
+            Entry::Punct(punct) => TokenTree::Punct(punct.clone()),
+            Entry::Literal(lit) => TokenTree::Literal(lit.clone()),
+        }
+    }
+}
+
+/// An immutable, owning buffer of the whole input, walked via a [`Cursor`].
+struct TokenBuffer {
+    entries: Vec<Entry>,
+}
+
+impl TokenBuffer {
+    /// Recursively buffers an entire `TokenStream`.
+    fn new(stream: TokenStream) -> Self {
+        let entries = stream
+            .into_iter()
+            .map(|tt| match tt {
+                TokenTree::Group(group) => {
+                    let inner = TokenBuffer::new(group.stream());
+                    Entry::Group(group, inner)
+                }
+                TokenTree::Ident(ident) => Entry::Ident(ident),
+                TokenTree::Punct(punct) => Entry::Punct(punct),
+                TokenTree::Literal(lit) => Entry::Literal(lit),
+            })
+            .collect();
+        TokenBuffer { entries }
+    }
+
+    /// Returns a cursor at the start of the buffer.
+    fn begin(&self) -> Cursor<'_> {
+        Cursor { buf: &self.entries, index: 0 }
+    }
+}
+
+/// A cheap, copyable pointer into a [`TokenBuffer`]; cloning it snapshots the
+/// position, so backtracking is just restoring a saved copy.
+#[derive(Clone, Copy)]
+struct Cursor<'a> {
+    buf: &'a [Entry],
+    index: usize,
+}
+
+impl<'a> Cursor<'a> {
+    /// Whether the cursor has reached the end of its buffer.
+    fn eof(self) -> bool {
+        self.index >= self.buf.len()
+    }
+
+    fn entry(self) -> Option<&'a Entry> {
+        self.buf.get(self.index)
+    }
+
+    fn bump(self) -> Cursor<'a> {
+        Cursor { buf: self.buf, index: self.index + 1 }
+    }
+
+    /// The next token of any kind, paired with the cursor past it.
+    fn token_tree(self) -> Option<(&'a Entry, Cursor<'a>)> {
+        self.entry().map(|entry| (entry, self.bump()))
+    }
+
+    /// The next token if it is an `Ident`.
+    fn ident(self) -> Option<(&'a Ident, Cursor<'a>)> {
+        match self.entry() {
+            Some(Entry::Ident(ident)) => Some((ident, self.bump())),
+            _ => None,
+        }
+    }
+
+    /// The next token if it is a `Punct`.
+    fn punct(self) -> Option<(&'a Punct, Cursor<'a>)> {
+        match self.entry() {
+            Some(Entry::Punct(punct)) => Some((punct, self.bump())),
+            _ => None,
+        }
+    }
+
+    /// The next token if it is a `Group` with the given delimiter, as a cursor
+    /// over its contents plus the cursor past the whole group.
+    fn group(self, delimiter: Delimiter) -> Option<(&'a Group, Cursor<'a>, Cursor<'a>)> {
+        match self.entry() {
+            Some(Entry::Group(group, inner)) if group.delimiter() == delimiter => {
+                Some((group, inner.begin(), self.bump()))
+            }
+            _ => None,
+        }
+    }
+
+    /// The remaining tokens from the cursor's position to the end of its buffer.
+    fn collect_remaining(mut self) -> TokenStream {
+        let mut out = TokenStream::new();
+        while let Some((entry, next)) = self.token_tree() {
+            out.extend(Some(entry.to_token_tree()));
+            self = next;
+        }
+        out
+    }
+}
 
 /// Wraps the inner content inside a block with boilerplate to create and return `__stream`.
-fn generate_quote_header(inner: TokenStream) -> TokenStream {
+///
+/// `span` is the expression whose value is stamped onto every literal token the
+/// block appends; it is bound once to `__span` so the expression is only
+/// evaluated a single time per block.
+fn generate_quote_header(inner: TokenStream, span: TokenStream) -> TokenStream {
     quote! {
         {
             let mut __stream = ::proc_quote::__rt::TokenStream::new();
+            let __span = (#span);
             #inner
             __stream
         }
@@ -49,7 +169,7 @@ fn parse_ident(stream: &mut TokenStream, ident: &Ident) {
     let span = ident.span();
     let ident = ident.to_string();
     stream.append_all(quote_spanned! { span=>
-        ::proc_quote::__rt::append_ident(#ref_mut_stream, #ident, ::proc_quote::__rt::Span::call_site());
+        ::proc_quote::__rt::append_ident(#ref_mut_stream, #ident, __span);
     });
 }
 
@@ -61,57 +181,85 @@ fn parse_punct(stream: &mut TokenStream, punct: &Punct) {
     let punct = punct.as_char();
     let append = match spacing {
         Spacing::Alone => quote_spanned! { span=>
-            ::proc_quote::__rt::append_punct(#ref_mut_stream, #punct, ::proc_quote::__rt::Spacing::Alone);
+            ::proc_quote::__rt::append_punct(#ref_mut_stream, #punct, ::proc_quote::__rt::Spacing::Alone, __span);
         },
         Spacing::Joint => quote_spanned! { span=>
-            ::proc_quote::__rt::append_punct(#ref_mut_stream, #punct, ::proc_quote::__rt::Spacing::Joint);
+            ::proc_quote::__rt::append_punct(#ref_mut_stream, #punct, ::proc_quote::__rt::Spacing::Joint, __span);
         },
     };
     stream.append_all(append);
 }
 
 /// Transforms a `Literal` into code that appends the given `Literal` into `__stream`.
-fn parse_literal(stream: &mut TokenStream, lit: &Literal) {
+fn parse_literal(stream: &mut TokenStream, lit: &Literal) -> Result<()> {
     let ref_mut_stream = quote!{ &mut __stream };
     let span = lit.span();
     let lit_to_string = lit.to_string();
 
-    if [
-        "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize",
-        "f32", "f64", "\"", "\'", "#",
-    ]
-    .iter()
-    .any(|suffix| lit_to_string.ends_with(suffix))
-    {
-        // Number with a suffix, char, str, raw char, raw str
-        // It should be safe to turn them into tokens
+    // Re-lex the literal through `proc_macro2` instead of guessing its shape
+    // from string suffixes, turning genuinely malformed input into a
+    // diagnostic here rather than a panic once the generated code runs.
+    match lit_to_string.parse::<TokenStream>() {
+        Ok(tokens) => {
+            let mut tokens = tokens.into_iter();
+            if !matches!((tokens.next(), tokens.next()), (Some(TokenTree::Literal(_)), None)) {
+                return Err(Error::new(span, "Unable to parse this literal."));
+            }
+        }
+        Err(_) => return Err(Error::new(span, "Unable to parse this literal.")),
+    }
+
+    // Integers and floats written without a suffix must be rebuilt through the
+    // `*_unsuffixed` constructors; splicing them directly would let `ToTokens`
+    // attach a default suffix such as `i32`. Every other form (suffixed number,
+    // char, str, byte str, raw string, …) is re-lexed from its own text at run
+    // time instead. Either way, the literal is stamped with `__span` before
+    // being appended, same as the idents, puncts and group delimiters written
+    // out in the block.
+    if let Ok(i) = lit_to_string.parse::<i32>() {
+        stream.append_all(quote_spanned! { span=>
+            {
+                let mut __lit = Literal::i32_unsuffixed(#i);
+                __lit.set_span(__span);
+                ::proc_quote::__rt::append_lit(#ref_mut_stream, __lit);
+            }
+        });
+    } else if let Ok(i) = lit_to_string.parse::<i64>() {
         stream.append_all(quote_spanned! { span=>
-            ::proc_quote::__rt::append_to_tokens(#ref_mut_stream, & #lit);
+            {
+                let mut __lit = Literal::i64_unsuffixed(#i);
+                __lit.set_span(__span);
+                ::proc_quote::__rt::append_lit(#ref_mut_stream, __lit);
+            }
+        });
+    } else if let Ok(u) = lit_to_string.parse::<u64>() {
+        stream.append_all(quote_spanned! { span=>
+            {
+                let mut __lit = Literal::u64_unsuffixed(#u);
+                __lit.set_span(__span);
+                ::proc_quote::__rt::append_lit(#ref_mut_stream, __lit);
+            }
+        });
+    } else if let Ok(f) = lit_to_string.parse::<f64>() {
+        stream.append_all(quote_spanned! { span=>
+            {
+                let mut __lit = Literal::f64_unsuffixed(#f);
+                __lit.set_span(__span);
+                ::proc_quote::__rt::append_lit(#ref_mut_stream, __lit);
+            }
         });
     } else {
-        // Integer without suffix, float without suffix
-        // Must be more careful, in order for the macro not to assume a wrong suffix
-        if let Ok(i) = lit_to_string.parse::<i32>() {
-            stream.append_all(quote_spanned! { span=>
-                ::proc_quote::__rt::append_lit(#ref_mut_stream, Literal::i32_unsuffixed(#i));
-            });
-        } else if let Ok(i) = lit_to_string.parse::<i64>() {
-            stream.append_all(quote_spanned! { span=>
-                ::proc_quote::__rt::append_lit(#ref_mut_stream, Literal::i64_unsuffixed(#i));
-            });
-        } else if let Ok(u) = lit_to_string.parse::<u64>() {
-            stream.append_all(quote_spanned! { span=>
-                ::proc_quote::__rt::append_lit(#ref_mut_stream, Literal::u64_unsuffixed(#u));
-            });
-        } else if let Ok(f) = lit_to_string.parse::<f64>() {
-            stream.append_all(quote_spanned! { span=>
-                ::proc_quote::__rt::append_lit(#ref_mut_stream, Literal::f64_unsuffixed(#f));
-            });
-        } else {
-            // This should never show up
-            panic!("Unable to parse this literal. Please, fill in an issue in `proc-macro`'s repository.");
-        }
+        stream.append_all(quote_spanned! { span=>
+            {
+                let mut __lit: Literal = #lit_to_string.parse()
+                    .expect("already validated when this literal was first parsed");
+                __lit.set_span(__span);
+                ::proc_quote::__rt::append_lit(#ref_mut_stream, __lit);
+            }
+        });
     }
+
+    Ok(())
 }
 
 /// Logic common to `parse_group` and `parse_group_in_iterator_pattern`.
@@ -133,83 +281,95 @@ fn parse_group_inner(stream: &mut TokenStream, inner: TokenStream, delimiter: De
     };
 
     stream.append_all(quote_spanned! { group_span =>
-        ::proc_quote::__rt::append_group(#ref_mut_stream, #inner, #delimiter);
+        ::proc_quote::__rt::append_group(#ref_mut_stream, #inner, #delimiter, __span);
     });
 }
 
 /// Transforms a `Group` into code that appends the given `Group` into `__stream`.
 ///
 /// Inside iterator patterns, use `parse_group_in_iterator_pattern`.
-fn parse_group(stream: &mut TokenStream, group: &Group) -> Result<()> {
-    let inner = parse_token_stream(group.stream())?;
-    let inner = generate_quote_header(inner);
+fn parse_group(stream: &mut TokenStream, group: &Group, inner: Cursor<'_>) -> Result<()> {
+    let inner = parse_token_stream(inner)?;
+    let inner = generate_quote_header(inner, quote!(__span));
 
     Ok(parse_group_inner(stream, inner, group.delimiter(), group.span()))
 }
 
 /// Transforms a `Group` into code that appends the given `Group` into `__stream`.
 ///
-/// This function is used inside the iterator patterns, to check for iterators used
-/// inside.
+/// This function is used inside the iterator patterns, so the group's contents
+/// are parsed with the enclosing loops' bound iterators in scope.
 fn parse_group_in_iterator_pattern(
     stream: &mut TokenStream,
     group: &Group,
-    iter_idents: &mut Vec<Ident>,
+    inner: Cursor<'_>,
+    bound: &[Ident],
 ) -> Result<()> {
-    let inner = parse_token_stream_in_iterator_pattern(group.stream(), iter_idents)?;
-    let inner = generate_quote_header(inner);
+    let inner = parse_token_stream_in_iterator_pattern(inner, bound)?;
+    let inner = generate_quote_header(inner, quote!(__span));
 
     Ok(parse_group_inner(stream, inner, group.delimiter(), group.span()))
 }
 
 /// Helper enum for `interpolation_pattern_type`'s return type.
-enum InterpolationPattern {
+enum InterpolationPattern<'a> {
     /// #ident
     Ident(Ident),
 
     /// #( group ) token_stream *
-    Iterator(Group, TokenStream),
+    ///
+    /// Holds a cursor over the group's contents, the group's span, and the
+    /// parsed separator.
+    Iterator(Cursor<'a>, Span, TokenStream),
+
+    /// #!expr
+    ///
+    /// Holds the expression (an ident or a parenthesized group's contents) and
+    /// the directive's span, which the generated splicing code is spanned with;
+    /// the tokens lexed at run time keep the spans the lexer gives them.
+    RawTokens(TokenStream, Span),
 
     /// Not an interpolation pattern
     None,
 }
 
-/// Helper type alias for `interpolation_pattern_type`'s input type.
-type InputIter = std::iter::Peekable<token_stream::IntoIter>;
-
-/// Returns the interpolation pattern type based on the content of the given 
-/// `punct` and the rest of the `input`.
-/// 
-/// Input that is part of the pattern is automatically consumed.
-fn interpolation_pattern_type(
+/// Returns the interpolation pattern type based on the content of the given
+/// `punct` and the tokens at `cursor` (which points just past the `punct`),
+/// along with the cursor positioned past whatever the pattern consumed.
+fn interpolation_pattern_type<'a>(
     punct: &Punct,
-    input: &mut InputIter,
-) -> Result<InterpolationPattern> {
-    match (punct.as_char(), input.peek()) {
+    cursor: Cursor<'a>,
+) -> Result<(InterpolationPattern<'a>, Cursor<'a>)> {
+    if punct.as_char() == '#' {
         // #ident
-        ('#', Some(TokenTree::Ident(_))) => {
-            if let Some(TokenTree::Ident(ident)) = input.next() {
-                Ok(InterpolationPattern::Ident(ident))
-            } else {
-                panic!("guaranteed by previous match")
+        if let Some((ident, after)) = cursor.ident() {
+            return Ok((InterpolationPattern::Ident(ident.clone()), after));
+        }
+
+        // #!expr
+        if let Some((bang, after)) = cursor.punct() {
+            if bang.as_char() == '!' {
+                if let Some((ident, after)) = after.ident() {
+                    return Ok((InterpolationPattern::RawTokens(quote!(#ident), ident.span()), after));
+                }
+                if let Some((group, _, after)) = after.group(Delimiter::Parenthesis) {
+                    return Ok((InterpolationPattern::RawTokens(group.stream(), group.span()), after));
+                }
+                // Not a raw-splice expression (e.g. an inner attribute like
+                // `#![no_std]`) — leave the `!` unconsumed and fall through to
+                // ordinary token handling.
             }
-        },
+        }
 
         // #(group)
-        ('#', Some(TokenTree::Group(group))) if group.delimiter() == Delimiter::Parenthesis => {
-            let inner = match input.next() {
-                Some(TokenTree::Group(inner)) => inner,
-                _ => panic!("guaranteed by previous match"),   
-            };
-
-            let separator = parse_separator(input, inner.span())?;
-
-            Ok(InterpolationPattern::Iterator(inner, separator))
-        },
-
-        // Not an interpolation pattern
-        _ => Ok(InterpolationPattern::None),
+        if let Some((group, inner, after)) = cursor.group(Delimiter::Parenthesis) {
+            let (separator, after) = parse_separator(after, group.span())?;
+            return Ok((InterpolationPattern::Iterator(inner, group.span(), separator), after));
+        }
     }
+
+    // Not an interpolation pattern
+    Ok((InterpolationPattern::None, cursor))
 }
 
 /// Interpolates the given variable, which should implement `ToTokens`.
@@ -221,28 +381,108 @@ fn interpolate_to_tokens_ident(stream: &mut TokenStream, ident: &Ident) {
     });
 }
 
-/// Interpolates the expression inside the group, which should evaluate to
-/// something that implements `ToTokens`.
-fn interpolate_iterator_group(stream: &mut TokenStream, group: &Group, separator: &TokenStream) -> Result<()> {
+/// Interpolates raw tokens lexed at run time from the given expression, which
+/// should evaluate to something that implements `AsRef<str>`.
+///
+/// The string is lexed through `proc_macro2`'s `FromStr`; a `LexError` turns
+/// into a runtime panic whose message quotes the offending string.
+fn interpolate_raw_tokens(stream: &mut TokenStream, expr: TokenStream, span: Span) {
+    let ref_mut_stream = quote!{ &mut __stream };
+    stream.append_all(quote_spanned! { span=>
+        {
+            let __raw: &str = (#expr).as_ref();
+            let __tokens = __raw.parse::<::proc_quote::__rt::TokenStream>()
+                .unwrap_or_else(|__err| panic!(
+                    "`quote!` failed to lex raw tokens from {:?}: {}", __raw, __err
+                ));
+            ::proc_quote::__rt::append_to_tokens(#ref_mut_stream, & __tokens);
+        }
+    });
+}
+
+/// Collects the iterator variables this repetition body interpolates at its
+/// own level (skipping `bound` and duplicates), stepping over nested `#(...)*`
+/// without descending into them since those bind their own iterators.
+///
+/// Errors found here must be returned here, or the generation pass re-walking
+/// the same tokens would misreport an empty collection as "no iterator in pattern".
+fn collect_iter_idents(mut cursor: Cursor<'_>, bound: &[Ident], out: &mut Vec<Ident>) -> Result<()> {
+    while let Some((entry, next)) = cursor.token_tree() {
+        match entry {
+            Entry::Group(_, inner) => {
+                collect_iter_idents(inner.begin(), bound, out)?;
+                cursor = next;
+            }
+            Entry::Ident(_) | Entry::Literal(_) => cursor = next,
+            Entry::Punct(punct) => match interpolation_pattern_type(punct, next)? {
+                (InterpolationPattern::Ident(ident), after) => {
+                    if !bound.iter().any(|i| i == &ident) && !out.iter().any(|i| i == &ident) {
+                        out.push(ident);
+                    }
+                    cursor = after;
+                }
+                // A nested `#(...)*` binds its own fresh iterators; skip over
+                // it rather than descending, so they aren't collected twice.
+                (InterpolationPattern::Iterator(_, _, _), after) => cursor = after,
+                // `#!expr` or a bare punct: nothing to collect.
+                (_, after) => cursor = after,
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Generates a `for` loop zipping this level's fresh iterator variables,
+/// running the interpolated body once per iteration.
+///
+/// `bound` is the complete set of iterators already zipped by enclosing loops;
+/// when non-empty, this loop is itself nested and re-runs per outer iteration,
+/// so it zips its fresh iterators by reference instead of by value.
+fn interpolate_iterator_group(
+    stream: &mut TokenStream,
+    inner: Cursor<'_>,
+    group_span: Span,
+    separator: &TokenStream,
+    bound: &[Ident],
+) -> Result<()> {
     let mut iter_idents = Vec::new();
+    collect_iter_idents(inner, bound, &mut iter_idents)?;
 
-    let output = parse_token_stream_in_iterator_pattern(group.stream(), &mut iter_idents)?;
+    let mut nested_bound = bound.to_vec();
+    nested_bound.extend(iter_idents.iter().cloned());
 
     let mut idents = iter_idents.iter();
     let first = match idents.next() {
         Some(first) => first,
-        None => return Err(Error::new(group.span(), "Expected at least one iterator inside pattern.")),
+        None => return Err(Error::new(group_span, "Expected at least one iterator inside pattern.")),
     };
     let first = quote!{ #first };
     let idents_in_tuple = idents.fold(first, |previous, next| quote!{ (#previous, #next) });
 
+    let output = parse_token_stream_in_iterator_pattern(inner, &nested_bound)?;
+
+    // When this loop sits inside an enclosing one (`bound` non-empty), its body
+    // runs once per outer iteration, so consuming `iter_idents` by value would
+    // move them out on the first pass and fail to borrow-check on the second.
+    // Iterate by reference instead; `#ident` already splices idents behind a
+    // `&`, so a loop variable bound to a reference works the same way.
+    let nested = !bound.is_empty();
+
     let mut idents = iter_idents.iter();
-    let first = match idents.next() {
-        Some(first) => first,
-        None => return Err(Error::new(group.span(), "Expected at least one iterator inside pattern.")),
+    let first = idents.next().expect("checked to be non-empty above");
+    let first_into_iter = if nested {
+        quote_spanned!(first.span()=> (& #first) .into_iter())
+    } else {
+        quote_spanned!(first.span()=> #first .into_iter())
     };
-    let first_into_iter = quote_spanned!(first.span()=> #first .into_iter());
-    let zip_iterators = idents.map(|ident| quote_spanned! { ident.span()=> .zip( #ident .into_iter() ) });
+    let zip_iterators = idents.map(|ident| {
+        if nested {
+            quote_spanned! { ident.span()=> .zip( (& #ident) .into_iter() ) }
+        } else {
+            quote_spanned! { ident.span()=> .zip( #ident .into_iter() ) }
+        }
+    });
     if separator.is_empty() {
         stream.append_all(quote! {
             for #idents_in_tuple in #first_into_iter #(#zip_iterators)* {
@@ -264,27 +504,40 @@ fn interpolate_iterator_group(stream: &mut TokenStream, group: &Group, separator
 }
 
 /// Parses the input according to `quote!` rules.
-fn parse_token_stream(input: TokenStream) -> Result<TokenStream> {
+fn parse_token_stream(mut cursor: Cursor<'_>) -> Result<TokenStream> {
     let mut output = TokenStream::new();
 
-    let mut input = input.into_iter().peekable();
-    while let Some(token) = input.next() {
-        match &token {
-            TokenTree::Group(group) => parse_group(&mut output, group)?,
-            TokenTree::Ident(ident) => parse_ident(&mut output, ident),
-            TokenTree::Literal(lit) => parse_literal(&mut output, lit),
-            TokenTree::Punct(punct) => {
-                match interpolation_pattern_type(&punct, &mut input)? {
+    while let Some((entry, next)) = cursor.token_tree() {
+        match entry {
+            Entry::Group(group, inner) => {
+                parse_group(&mut output, group, inner.begin())?;
+                cursor = next;
+            }
+            Entry::Ident(ident) => {
+                parse_ident(&mut output, ident);
+                cursor = next;
+            }
+            Entry::Literal(lit) => {
+                parse_literal(&mut output, lit)?;
+                cursor = next;
+            }
+            Entry::Punct(punct) => {
+                let (pattern, after) = interpolation_pattern_type(punct, next)?;
+                match pattern {
                     InterpolationPattern::Ident(ident) => {
                         interpolate_to_tokens_ident(&mut output, &ident)
                     },
-                    InterpolationPattern::Iterator(group, separator) => {
-                        interpolate_iterator_group(&mut output, &group, &separator)?
+                    InterpolationPattern::Iterator(inner, span, separator) => {
+                        interpolate_iterator_group(&mut output, inner, span, &separator, &[])?
+                    },
+                    InterpolationPattern::RawTokens(expr, span) => {
+                        interpolate_raw_tokens(&mut output, expr, span)
                     },
                     InterpolationPattern::None => {
                         parse_punct(&mut output, punct);
                     },
                 }
+                cursor = after;
             }
         }
     }
@@ -293,37 +546,46 @@ fn parse_token_stream(input: TokenStream) -> Result<TokenStream> {
 }
 
 /// Parses the input according to `quote!` rules inside an iterator pattern.
+///
+/// `bound` is the complete set of iterator variables the enclosing loops bind,
+/// so a nested `#(...)*` only zips over variables fresh to it.
 fn parse_token_stream_in_iterator_pattern(
-    input: TokenStream,
-    iter_idents: &mut Vec<Ident>,
+    mut cursor: Cursor<'_>,
+    bound: &[Ident],
 ) -> Result<TokenStream> {
     let mut output = TokenStream::new();
 
-    let mut input = input.into_iter().peekable();
-    while let Some(token) = input.next() {
-        match &token {
-            TokenTree::Group(group) => {
-                parse_group_in_iterator_pattern(&mut output, group, iter_idents)?
+    while let Some((entry, next)) = cursor.token_tree() {
+        match entry {
+            Entry::Group(group, inner) => {
+                parse_group_in_iterator_pattern(&mut output, group, inner.begin(), bound)?;
+                cursor = next;
             }
-            TokenTree::Ident(ident) => parse_ident(&mut output, ident),
-            TokenTree::Literal(lit) => parse_literal(&mut output, lit),
-            TokenTree::Punct(punct) => {
-                match interpolation_pattern_type(&punct, &mut input)? {
+            Entry::Ident(ident) => {
+                parse_ident(&mut output, ident);
+                cursor = next;
+            }
+            Entry::Literal(lit) => {
+                parse_literal(&mut output, lit)?;
+                cursor = next;
+            }
+            Entry::Punct(punct) => {
+                let (pattern, after) = interpolation_pattern_type(punct, next)?;
+                match pattern {
                     InterpolationPattern::Ident(ident) => {
-                        interpolate_to_tokens_ident(&mut output, &ident);
-                        if !iter_idents.iter().any(|i| i == &ident) {
-                            iter_idents.push(ident);
-                        }
+                        interpolate_to_tokens_ident(&mut output, &ident)
                     },
-                    InterpolationPattern::Iterator(group, separator) => {
-                        let span_s = group.span();
-                        let span_e = separator.into_iter().last().map(|s| s.span()).unwrap_or(span_s);
-                        return Err(Error::new(span_s, "Nested iterator patterns not supported.").end_span(span_e));
+                    InterpolationPattern::Iterator(inner, span, separator) => {
+                        interpolate_iterator_group(&mut output, inner, span, &separator, bound)?
+                    },
+                    InterpolationPattern::RawTokens(expr, span) => {
+                        interpolate_raw_tokens(&mut output, expr, span)
                     },
                     InterpolationPattern::None => {
                         parse_punct(&mut output, punct);
                     },
                 }
+                cursor = after;
             }
         }
     }
@@ -331,36 +593,52 @@ fn parse_token_stream_in_iterator_pattern(
     Ok(output)
 }
 
-/// Parses the input according to `quote!` rules in an iterator pattern, between 
+/// Parses the input according to `quote!` rules in an iterator pattern, between
 /// the parenthesis and the asterisk.
-fn parse_separator(input: &mut InputIter, iterators_span: Span) -> Result<TokenStream> {
+fn parse_separator(mut cursor: Cursor<'_>, iterators_span: Span) -> Result<(TokenStream, Cursor<'_>)> {
     let mut output = TokenStream::new();
 
-    while let Some(token) = input.next() {
-        match &token {
-            TokenTree::Group(group) => parse_group(&mut output, group)?,
-            TokenTree::Ident(ident) => parse_ident(&mut output, ident),
-            TokenTree::Literal(lit) => parse_literal(&mut output, lit),
-            TokenTree::Punct(punct) => {
-                if punct.as_char() == '*' {
-                    // The asterisk marks the end of the iterator pattern
-                    return Ok(output);
-                } else {
-                    match interpolation_pattern_type(&punct, input)? {
-                        InterpolationPattern::Ident(ident) => {
-                            // TODO don't allow iterator variables
-                            interpolate_to_tokens_ident(&mut output, &ident)
-                        },
-                        InterpolationPattern::Iterator(group, separator) => {
-                            let span_s = group.span();
-                            let span_e = separator.into_iter().last().map(|s| s.span()).unwrap_or(span_s);
-                            return Err(Error::new(span_s, "Nested iterator patterns not supported.").end_span(span_e));
-                        },
-                        InterpolationPattern::None => {
-                            parse_punct(&mut output, punct);
-                        },
-                    }
+    while !cursor.eof() {
+        // The asterisk marks the end of the iterator pattern.
+        if let Some((punct, after)) = cursor.punct() {
+            if punct.as_char() == '*' {
+                return Ok((output, after));
+            }
+        }
+
+        let (entry, next) = cursor.token_tree().expect("not at eof");
+        match entry {
+            Entry::Group(group, inner) => {
+                parse_group(&mut output, group, inner.begin())?;
+                cursor = next;
+            }
+            Entry::Ident(ident) => {
+                parse_ident(&mut output, ident);
+                cursor = next;
+            }
+            Entry::Literal(lit) => {
+                parse_literal(&mut output, lit)?;
+                cursor = next;
+            }
+            Entry::Punct(punct) => {
+                let (pattern, after) = interpolation_pattern_type(punct, next)?;
+                match pattern {
+                    InterpolationPattern::Ident(ident) => {
+                        // TODO don't allow iterator variables
+                        interpolate_to_tokens_ident(&mut output, &ident)
+                    },
+                    InterpolationPattern::Iterator(_, span_s, separator) => {
+                        let span_e = separator.into_iter().last().map(|s| s.span()).unwrap_or(span_s);
+                        return Err(Error::new(span_s, "Nested iterator patterns not supported.").end_span(span_e));
+                    },
+                    InterpolationPattern::RawTokens(expr, span) => {
+                        interpolate_raw_tokens(&mut output, expr, span)
+                    },
+                    InterpolationPattern::None => {
+                        parse_punct(&mut output, punct);
+                    },
                 }
+                cursor = after;
             }
         }
     }
@@ -368,9 +646,162 @@ fn parse_separator(input: &mut InputIter, iterators_span: Span) -> Result<TokenS
     Err(Error::new(iterators_span, "Iterating interpolation does not have `*` symbol."))
 }
 
+/// Splits a `quote_spanned!` input into the leading span expression and the
+/// token stream that follows the `=>` separator.
+fn parse_span_expr(input: TokenStream) -> Result<(TokenStream, TokenStream)> {
+    let buffer = TokenBuffer::new(input);
+    let mut cursor = buffer.begin();
+    let mut span = TokenStream::new();
+
+    while let Some((entry, next)) = cursor.token_tree() {
+        if let Entry::Punct(punct) = entry {
+            if punct.as_char() == '=' && punct.spacing() == Spacing::Joint {
+                if let Some((arrow, after)) = next.punct() {
+                    if arrow.as_char() == '>' {
+                        return Ok((span, after.collect_remaining()));
+                    }
+                }
+            }
+        }
+        span.extend(Some(entry.to_token_tree()));
+        cursor = next;
+    }
+
+    Err(Error::new(
+        Span::call_site(),
+        "Expected a `span => ...` separator in `quote_spanned!`.",
+    ))
+}
+
 pub fn quote(input: TokenStream) -> TokenStream {
-    match parse_token_stream(input) {
-        Ok(output) => generate_quote_header(output),
+    let buffer = TokenBuffer::new(input);
+    match parse_token_stream(buffer.begin()) {
+        Ok(output) => generate_quote_header(output, quote!(::proc_quote::__rt::Span::call_site())),
         Err(err) => err.raise(),
     }
 }
+
+pub fn quote_spanned_impl(input: TokenStream) -> TokenStream {
+    let (span, input) = match parse_span_expr(input) {
+        Ok(split) => split,
+        Err(err) => return err.raise(),
+    };
+    let buffer = TokenBuffer::new(input);
+    match parse_token_stream(buffer.begin()) {
+        Ok(output) => generate_quote_header(output, span),
+        Err(err) => err.raise(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    // These check the shape of the generated code rather than running it:
+    // the generated tokens reference `::proc_quote::__rt`, the runtime module
+    // that lives in the companion `proc-quote` crate, which isn't reachable
+    // from this crate's own test binary.
+    fn expand(src: &str) -> String {
+        let input = TokenStream::from_str(src).expect("test input should lex");
+        quote(input).to_string()
+    }
+
+    fn expand_spanned(src: &str) -> String {
+        let input = TokenStream::from_str(src).expect("test input should lex");
+        quote_spanned_impl(input).to_string()
+    }
+
+    #[test]
+    fn quote_spanned_threads_its_span_through_a_nested_group() {
+        let out = expand_spanned("my_span => { #ident }");
+        // The caller's span is bound once at the top...
+        assert!(out.contains("let __span = (my_span) ;"));
+        // ...and the nested group's own header reuses that binding instead of
+        // falling back to `Span::call_site()`.
+        assert!(out.contains("let __span = (__span) ;"));
+        assert!(!out.contains("call_site"));
+    }
+
+    #[test]
+    fn quote_spanned_stamps_its_span_onto_literals() {
+        let out = expand_spanned("my_span => 1 + \"s\"");
+        // Both the unsuffixed-integer path and the re-lexed path must stamp
+        // the caller's span onto the literal they construct at run time,
+        // instead of leaving it at the literal's own default span.
+        assert!(out.contains("Literal :: i32_unsuffixed (1i32) ; __lit . set_span (__span)"));
+        assert!(out.contains(". parse () . expect (\"already validated when this literal was first parsed\") ; __lit . set_span (__span)"));
+    }
+
+    #[test]
+    fn nested_repetition_borrows_the_inner_loops_fresh_iterator() {
+        let out = expand("#( #a #( #b )* )*");
+        // `a` is this loop's own binding, consumed once as usual...
+        assert!(out.contains("for a in a . into_iter ()"));
+        // ...but `b` is fresh to the loop nested inside it, so it must be
+        // borrowed rather than moved to survive more than one outer pass.
+        assert!(out.contains("for b in (& b) . into_iter ()"));
+    }
+
+    #[test]
+    fn repetition_with_no_fresh_iterator_is_an_error() {
+        // `v` belongs to the inner repetition, not this one, so the outer
+        // level has nothing of its own to zip over.
+        let out = expand("#( #( #v )* )*");
+        assert!(out.contains("Expected at least one iterator inside pattern."));
+    }
+
+    #[test]
+    fn bang_not_followed_by_an_expression_falls_through_to_plain_tokens() {
+        // `#![no_std]` tokenizes as `#`, `!`, `[no_std]`; since the `!` isn't
+        // followed by an ident or a parenthesized group, it must fall through
+        // to ordinary token handling instead of erroring, so inner attributes
+        // keep working.
+        let out = expand("#![no_std]");
+        assert!(out.contains("append_punct (& mut __stream , '#'"));
+        assert!(out.contains("append_punct (& mut __stream , '!'"));
+        assert!(out.contains("append_group"));
+    }
+
+    #[test]
+    fn bare_hash_before_a_non_interpolation_punct_is_left_unconsumed() {
+        // `+` is neither an ident, `!`, nor a paren group, so the cursor
+        // peeked past `#` must be left untouched for the main loop to parse
+        // `+` as its own token rather than swallowing it as lookahead.
+        let out = expand("#+");
+        assert!(out.contains("append_punct (& mut __stream , '#'"));
+        assert!(out.contains("append_punct (& mut __stream , '+'"));
+    }
+
+    #[test]
+    fn relexes_hex_integer_literal() {
+        assert!(expand("0x1Fu32").contains("0x1Fu32"));
+    }
+
+    #[test]
+    fn relexes_byte_string_literal() {
+        // Re-lexed at run time from its own text, so its exact source form
+        // shows up as an escaped string constant rather than a raw splice.
+        assert!(expand(r#"b"abc""#).contains(r#""b\"abc\"""#));
+    }
+
+    #[test]
+    fn relexes_raw_string_literal() {
+        assert!(expand(r##"r"abc""##).contains(r##""r\"abc\"""##));
+    }
+
+    #[test]
+    fn bang_ident_splice_parses_the_expression_at_run_time() {
+        let out = expand("#!expr");
+        assert!(out.contains(". as_ref ()"));
+        assert!(out.contains(". parse :: <"));
+        assert!(out.contains("panic ! (\"`quote!` failed to lex raw tokens from"));
+    }
+
+    #[test]
+    fn bang_paren_splice_accepts_an_arbitrary_expression() {
+        let out = expand("#!(format!(\"{}\", 1))");
+        assert!(out.contains("format ! (\"{}\" , 1)"));
+        assert!(out.contains(". parse :: <"));
+    }
+}